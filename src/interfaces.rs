@@ -0,0 +1,90 @@
+
+use ::std;
+use ::std::ffi::CStr;
+use ::std::io;
+use ::std::net::IpAddr;
+use ::std::ptr;
+use ::libc;
+
+use ::RawSocketAddr;
+
+/// A network interface, as enumerated by [`interfaces`]
+#[derive(Clone, Debug)]
+pub struct InterfaceAddr {
+    /// Interface name (e.g. `"eth0"`, `"lo"`)
+    pub name: String,
+    /// Interface index, as used by `ipi_ifindex`/`ipi6_ifindex` and `send_sas_if`
+    pub index: u32,
+    /// Address assigned to the interface, if any
+    pub addr: Option<IpAddr>,
+    /// Netmask associated with `addr`, if any
+    pub netmask: Option<IpAddr>,
+    /// Broadcast (or point-to-point destination) address associated with `addr`, if any
+    pub broadcast: Option<IpAddr>,
+}
+
+/// Enumerate the system's network interfaces and their addresses
+///
+/// This walks the `getifaddrs(3)` linked list, resolving each entry's interface name to an index
+/// via `if_nametoindex(3)` and converting its addresses through [`RawSocketAddr`]. This pairs
+/// naturally with [`send_sas_if`][crate::UdpSas::send_sas_if]: enumerate the host's links at
+/// startup, then pick the `(addr, index)` pair to reply from.
+pub fn interfaces() -> io::Result<Vec<InterfaceAddr>>
+{
+    let mut head: *mut libc::ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut head) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut out = Vec::new();
+    let mut ifa = head;
+    while !ifa.is_null() {
+        let entry = unsafe { &*ifa };
+
+        out.push(InterfaceAddr{
+            name:      unsafe { CStr::from_ptr(entry.ifa_name) }.to_string_lossy().into_owned(),
+            index:     unsafe { libc::if_nametoindex(entry.ifa_name) },
+            addr:      unsafe { to_ip(entry.ifa_addr) },
+            netmask:   unsafe { to_ip(entry.ifa_netmask) },
+            broadcast: unsafe { to_ip(entry.ifa_ifu) },
+        });
+
+        ifa = entry.ifa_next;
+    }
+
+    unsafe { libc::freeifaddrs(head); }
+    Ok(out)
+}
+
+unsafe fn to_ip(sa: *mut libc::sockaddr) -> Option<IpAddr>
+{
+    if sa.is_null() {
+        return None;
+    }
+
+    let len = match (*sa).sa_family as libc::c_int {
+        libc::AF_INET  => std::mem::size_of::<libc::sockaddr_in>(),
+        libc::AF_INET6 => std::mem::size_of::<libc::sockaddr_in6>(),
+        _ => return None,
+    };
+
+    RawSocketAddr::from_raw_parts(sa as *const u8, len)
+        .into_addr()
+        .map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interfaces_contains_loopback()
+    {
+        let ifaces = interfaces().unwrap();
+        let lo = ifaces.iter().find(|i| i.addr == Some("127.0.0.1".parse().unwrap()))
+            .expect("no interface with address 127.0.0.1");
+        // the netmask comes through the same RawSocketAddr conversion as addr; checking it too
+        // catches a conversion that only happens to get the address right
+        assert_eq!(lo.netmask, Some("255.0.0.0".parse().unwrap()));
+    }
+}
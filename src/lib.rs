@@ -75,138 +75,35 @@ extern crate os_socketaddr;
 
 use std::io;
 use std::net::{UdpSocket,ToSocketAddrs, SocketAddr, IpAddr};
-use std::os::unix::io::{AsRawFd,RawFd};
 
-use os_socketaddr::OsSocketAddr;
-
-// C glue
-#[link(name="rust_udp_sas", kind="static")]
-extern {
-    static udp_sas_IPV6_RECVPKTINFO: libc::c_int;
-    static udp_sas_IP_PKTINFO: libc::c_int;
-    fn udp_sas_recv(sock: libc::c_int, 
-                 buf: *mut u8, buf_len: libc::size_t, flags: libc::c_int,
-                 src: *mut libc::sockaddr, src_len: libc::socklen_t,
-                 dst: *mut libc::sockaddr, dst_len: libc::socklen_t,
-                 ) -> libc::ssize_t;
-
-    fn udp_sas_send(sock: libc::c_int, 
-
-                 buf: *const u8, buf_len: libc::size_t, flags: libc::c_int,
-                 src: *const libc::sockaddr, src_len: libc::socklen_t,
-                 dst: *const libc::sockaddr, dst_len: libc::socklen_t,
-                 ) -> libc::ssize_t;
-}
-use self::udp_sas_IP_PKTINFO as IP_PKTINFO;
-use self::udp_sas_IPV6_RECVPKTINFO as IPV6_RECVPKTINFO;
-
-macro_rules! try_io {
-    ($x:expr) => {
-        match $x {
-            -1 => {return Err(io::Error::last_os_error());},
-            x  => x
-            }}
-}
-
-fn getsockopt<T>(socket: RawFd, level: libc::c_int, name: libc::c_int, value: &mut T)
-    -> io::Result<libc::socklen_t>
-{
-    unsafe {
-        let mut len = std::mem::size_of::<T>() as libc::socklen_t;
-        try_io!(libc::getsockopt(socket, level, name,
-                                 value as *mut T as *mut libc::c_void,
-                                 &mut len));
-        Ok(len)
-    }
-}
-fn setsockopt<T>(socket: RawFd, level: libc::c_int, name: libc::c_int, value: &T)
-    -> io::Result<()>
-{
-    unsafe {
-        try_io!(libc::setsockopt(socket, level, name,
-                                 value as *const T as *const libc::c_void,
-                                 std::mem::size_of::<T>() as libc::socklen_t));
-        Ok(())
-    }
-}
-
-/// enable IP_PKTINFO/IPV6_RECVPKTINFO on a socket
-pub fn set_pktinfo(socket: RawFd) -> io::Result<()>
-{
-    unsafe {
-        let mut domain = libc::c_int::default();
-        getsockopt(socket, libc::SOL_SOCKET, libc::SO_DOMAIN, &mut domain)?;
-
-        let (level, option) = match domain {
-            libc::AF_INET  => (libc::IPPROTO_IP,   IP_PKTINFO),
-            libc::AF_INET6 => (libc::IPPROTO_IPV6, IPV6_RECVPKTINFO),
-            _ => { return Err(io::Error::new(io::ErrorKind::Other, "not an inet socket")); }
-        };
-
-        setsockopt(socket, level, option, &(1 as libc::c_int))
-    }
-}
-
-
-/// Receive a datagram (low-level function)
-/// 
-/// Parameters
-/// 
-/// * `buf`: buffer for storing the payload
-/// 
-/// Returns a tuple containing:
-/// 
-///   * the size of the payload
-///   * the source socket address (peer)
-///   * the destination ip address (local)
-/// 
-/// Note: the source (peer) and destination (local) addresses may not be present in the result if
-/// the underlying socket does not provide them.
-pub fn recv_sas(socket: RawFd, buf: &mut [u8])
-    -> io::Result<(usize, Option<SocketAddr>, Option<IpAddr>)>
-{
-    let mut src = OsSocketAddr::new();
-    let mut dst = OsSocketAddr::new();
-    
-    let nb = {
-        unsafe {udp_sas_recv(socket,
-                             buf.as_mut_ptr(), buf.len(), 0,
-                             src.as_mut_ptr(), src.capacity() as libc::socklen_t,
-                             dst.as_mut_ptr(), dst.capacity() as libc::socklen_t,
-                             )}
-    };
-
-    if nb < 0 {
-        Err(io::Error::last_os_error())
-    } else {
-        Ok((nb as usize, src.into(), dst.into_addr().map(|addr| addr.ip())))
-    }
-}
-
-/// Send datagram (low-level function)
-/// 
-/// Return the size of the sent payload
-/// 
-/// Note: the source (local) and destination (target) addresses are optional.
-pub fn send_sas(socket: RawFd, buf: &[u8], target: Option<&SocketAddr>, local: Option<&IpAddr>)
-    -> io::Result<usize>
-{
-    let src = match local {
-        None     => OsSocketAddr::new(),
-        Some(ip) => SocketAddr::new(*ip, 0).into()
-    };
-    let dst : OsSocketAddr = target.map(|a|*a).into();
-
-    let nb = unsafe { udp_sas_send(socket,
-                                   buf.as_ptr(), buf.len(), 0,
-                                   src.as_ptr(), src.len() as libc::socklen_t,
-                                   dst.as_ptr(), dst.len() as libc::socklen_t)};
-    if nb < 0 {
-        Err(io::Error::last_os_error())
-    } else {
-        Ok(nb as usize)
-    }
-}
+#[cfg(unix)]
+mod rawsocketaddr;
+#[cfg(unix)]
+pub use self::rawsocketaddr::RawSocketAddr;
+
+#[cfg(unix)]
+mod interfaces;
+#[cfg(unix)]
+pub use self::interfaces::{interfaces, InterfaceAddr};
+
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+pub use self::unix::{set_pktinfo, recv_sas, send_sas, send_sas_if, UdpEndpoint, recv_endpoint, send_endpoint,
+                      recv_sas_batch, send_sas_batch, BatchRecvResult, BatchSendItem};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+fn raw_handle(sock: &UdpSocket) -> std::os::unix::io::RawFd { sock.as_raw_fd() }
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub use self::windows::{set_pktinfo, recv_sas, send_sas, send_sas_if, UdpEndpoint, recv_endpoint, send_endpoint};
+#[cfg(windows)]
+use std::os::windows::io::AsRawSocket;
+#[cfg(windows)]
+fn raw_handle(sock: &UdpSocket) -> std::os::windows::io::RawSocket { sock.as_raw_socket() }
 
 /// An extension trait to support source address selection in `std::net::UdpSocket`
 /// 
@@ -243,29 +140,65 @@ pub trait UdpSas : Sized
     fn send_sas(&self, buf: &[u8], target: &SocketAddr, local: &IpAddr) -> io::Result<usize>;
 
     /// Receive a datagram
-    /// 
+    ///
     /// On success, returns a tuple `(nb, source, local)` containing the number of bytes read, the
     /// source socket address (peer address), and the destination ip address (local address).
-    /// 
+    ///
     fn recv_sas(&self, buf: &mut[u8]) -> io::Result<(usize, SocketAddr, IpAddr)>;
+
+    /// Sends a datagram to the given `target` address, using the `local` address as its
+    /// source and pinning the outgoing interface to `ifindex`.
+    ///
+    /// This is useful on multi-homed hosts where several interfaces share an address range, so
+    /// the local address alone is not enough to route the reply out of the correct link.
+    ///
+    /// On success, returns the number of bytes written.
+    fn send_sas_if(&self, buf: &[u8], target: &SocketAddr, local: &IpAddr, ifindex: u32) -> io::Result<usize>;
+
+    /// Receive a datagram, additionally reporting the interface it arrived on
+    ///
+    /// On success, returns a tuple `(nb, source, local, ifindex)` containing the number of bytes
+    /// read, the source socket address (peer address), the destination ip address (local
+    /// address), and the interface index the datagram arrived on.
+    ///
+    /// Note: `ifindex` may be `None` if the underlying socket does not provide it.
+    fn recv_sas_if(&self, buf: &mut[u8]) -> io::Result<(usize, SocketAddr, IpAddr, Option<u32>)>;
+
+    /// Receive a datagram, capturing its peer address and reply source information as a
+    /// [`UdpEndpoint`] rather than decoding them into `std::net` types.
+    ///
+    /// This is useful for servers that answer many datagrams: the `UdpEndpoint` can be stashed
+    /// and later handed to [`send_endpoint`][UdpSas::send_endpoint] to reply, which is cheaper
+    /// than reconstructing a `SocketAddr`/`IpAddr` pair with `recv_sas`/`send_sas` on every
+    /// exchange.
+    ///
+    /// On success, returns a tuple `(nb, endpoint)` containing the number of bytes read and the
+    /// captured endpoint.
+    fn recv_endpoint(&self, buf: &mut[u8]) -> io::Result<(usize, UdpEndpoint)>;
+
+    /// Sends a datagram to the peer captured by `endpoint`, using the source address and
+    /// interface the original datagram was received on.
+    ///
+    /// On success, returns the number of bytes written.
+    fn send_endpoint(&self, endpoint: &UdpEndpoint, buf: &[u8]) -> io::Result<usize>;
 }
 
 impl UdpSas for UdpSocket
 {
     fn bind_sas<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
         let sock = UdpSocket::bind(addr)?;
-        set_pktinfo(sock.as_raw_fd())?;
+        set_pktinfo(raw_handle(&sock))?;
         Ok(sock)
     }
 
     fn send_sas(&self, buf: &[u8], target: &SocketAddr, local: &IpAddr) -> io::Result<usize>
     {
-        send_sas(self.as_raw_fd(), buf, Some(target), Some(local))
+        send_sas(raw_handle(self), buf, Some(target), Some(local))
     }
 
     fn recv_sas(&self, buf: &mut[u8]) -> io::Result<(usize, SocketAddr, IpAddr)>
     {
-        let (nb, src, local) = recv_sas(self.as_raw_fd(), buf)?;
+        let (nb, src, local, _ifindex) = recv_sas(raw_handle(self), buf)?;
         match (src, local) {
             (Some(src), Some(local)) => Ok((nb, src, local)),
             (None, _) => Err(io::Error::new(
@@ -277,6 +210,36 @@ impl UdpSas for UdpSocket
                     )),
         }
     }
+
+    fn send_sas_if(&self, buf: &[u8], target: &SocketAddr, local: &IpAddr, ifindex: u32) -> io::Result<usize>
+    {
+        send_sas_if(raw_handle(self), buf, Some(target), Some(local), Some(ifindex))
+    }
+
+    fn recv_sas_if(&self, buf: &mut[u8]) -> io::Result<(usize, SocketAddr, IpAddr, Option<u32>)>
+    {
+        let (nb, src, local, ifindex) = recv_sas(raw_handle(self), buf)?;
+        match (src, local) {
+            (Some(src), Some(local)) => Ok((nb, src, local, ifindex)),
+            (None, _) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "source address not available (maybe the socket is connected)")),
+            (_, None) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "local address not available (IP_PKTINFO/IPV6_RECVPKTINFO may not be enabled on the socket)"
+                    )),
+        }
+    }
+
+    fn recv_endpoint(&self, buf: &mut[u8]) -> io::Result<(usize, UdpEndpoint)>
+    {
+        recv_endpoint(raw_handle(self), buf)
+    }
+
+    fn send_endpoint(&self, endpoint: &UdpEndpoint, buf: &[u8]) -> io::Result<usize>
+    {
+        send_endpoint(raw_handle(self), endpoint, buf)
+    }
 }
 
 
@@ -1,20 +1,28 @@
 
 use ::std;
-use ::std::net::SocketAddr;
+use ::std::ffi::OsStr;
+use ::std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use ::std::os::unix::ffi::OsStrExt;
+use ::std::path::PathBuf;
 use ::libc;
 
-/// A type for handling conversions between std::net::SocketAddr and libc::{sockaddr_in,sockaddr_in6}
-/// 
-/// This type contains just a buffer enough big to hold a `libc::sockaddr_in` or
-/// `libc::sockaddr_in6` struct.
-/// 
-/// Its content can be arbitrary written using `.as_mut()`. Then a call to `.into_addr()` will
-/// attempt to convert it into `std::net::SocketAddr`.
+/// A type for handling conversions between `std::net::SocketAddr` and raw socket addresses of
+/// arbitrary families (`AF_INET`, `AF_INET6`, `AF_UNIX`, ...).
+///
+/// This type is backed by a `libc::sockaddr_storage` buffer, which is big enough to hold any
+/// address family known to the system, plus an explicit `socklen_t` length tracking how much of
+/// that buffer actually holds valid address data (the two are not the same: the buffer is always
+/// full size, so that the type can be filled in place by calls like `recvfrom`/`getsockname`).
+///
+/// Its content can be arbitrary written using `.as_mut()`; the caller is then responsible for
+/// recording the actual address length with `.set_len()`. A call to `.into_addr()` will attempt
+/// to convert it into `std::net::SocketAddr`, and `.into_path()` into a unix socket path.
 ///
 #[derive(Copy,Clone)]
 pub struct RawSocketAddr
 {
-    sa6: libc::sockaddr_in6
+    storage: libc::sockaddr_storage,
+    len: libc::socklen_t,
 }
 
 #[allow(dead_code)]
@@ -23,85 +31,121 @@ impl RawSocketAddr {
     /// Create a new empty socket address
     pub fn new() -> Self
     {
-        RawSocketAddr{sa6: unsafe { std::mem::zeroed() }}
+        RawSocketAddr{storage: unsafe { std::mem::zeroed() }, len: 0}
     }
 
     /// Create a new socket address from a raw slice
-    /// 
-    /// This function will fill the internal buffer with the slice pointed by (`ptr`, `len`). If
-    /// `len` is greater than the buffer size then the input is truncated.
-    /// 
+    ///
+    /// This function will fill the internal buffer with the slice pointed by (`ptr`, `len`), and
+    /// record `len` as the address length.
+    ///
     /// # Panics
-    /// 
-    /// Panics if `len` is bigger that the size of `libc::sockaddr_in6`
-    /// 
+    ///
+    /// Panics if `len` is bigger than the size of `libc::sockaddr_storage`. Since that buffer is
+    /// big enough to hold any address family known to the system, this should never happen in
+    /// practice.
+    ///
     pub unsafe fn from_raw_parts(ptr: *const u8, len: usize) -> Self
     {
         let mut raw = RawSocketAddr::new();
-        assert!(len <= std::mem::size_of_val(&raw.sa6));
+        assert!(len <= std::mem::size_of_val(&raw.storage));
         raw.as_mut()[..len].copy_from_slice(std::slice::from_raw_parts(ptr, len));
+        raw.len = len as libc::socklen_t;
         raw
     }
 
+    /// Create a new socket address from a raw `libc::sockaddr_storage` and its length
+    pub fn from_raw(storage: libc::sockaddr_storage, len: libc::socklen_t) -> Self
+    {
+        RawSocketAddr{storage, len}
+    }
+
     /// Create a new socket address from a `std::net::SocketAddr` object
     pub fn from(addr: Option<&SocketAddr>) -> Self
     {
-        RawSocketAddr{sa6: unsafe {
-            match addr {
-                None => std::mem::zeroed(),
-                Some(&SocketAddr::V4(addr)) => {
-                    let mut sa6 = std::mem::uninitialized();
-                    *(&mut sa6 as *mut _ as *mut _) = addr;
-                    sa6
-                },
-                Some(&SocketAddr::V6(addr)) =>
-                    *(&addr as *const _ as *const _),
-            }
-        }}
+        match addr {
+            None => RawSocketAddr::new(),
+            Some(addr) => (*addr).into(),
+        }
     }
 
     /// Attempt to convert the internal buffer into a `std::net::SocketAddr` object
-    /// 
+    ///
     /// The internal buffer is assumed to be a `libc::sockaddr`.
-    /// 
-    /// If the value of `.sa_family` resolves to `AF_INET` or `AF_INET6` then the buffer is
+    ///
+    /// If the value of `.family()` resolves to `AF_INET` or `AF_INET6` then the buffer is
     /// converted into `SocketAddr`, otherwise the function returns None.
-    /// 
+    ///
     pub fn into_addr(self) -> Option<SocketAddr>
     {
         self.into()
     }
 
-    /// Return the length of the address
-    /// 
-    /// The result depends on the value of `.sa_family` in the internal buffer:
-    /// * `AF_INET`  -> the size of `sockaddr_in`
-    /// * `AF_INET6` -> the size of `sockaddr_in6`
-    /// * *other* -> 0
-    /// 
-    pub fn len(&self) -> usize
+    /// Attempt to interpret the internal buffer as a unix socket address, returning its path
+    ///
+    /// Returns `None` if `.family()` is not `AF_UNIX`, and an empty path for an unnamed socket.
+    ///
+    pub fn into_path(self) -> Option<PathBuf>
     {
-        match self.sa6.sin6_family as i32 {
-            libc::AF_INET  => std::mem::size_of::<libc::sockaddr_in >(),
-            libc::AF_INET6 => std::mem::size_of::<libc::sockaddr_in6>(),
-            _ => 0
+        if self.family() as libc::c_int != libc::AF_UNIX {
+            return None;
+        }
+
+        unsafe {
+            let sun  = &*(&self.storage as *const _ as *const libc::sockaddr_un);
+            let base = &sun.sun_path as *const _ as usize - &self.storage as *const _ as usize;
+
+            if (self.len as usize) <= base {
+                return Some(PathBuf::new());
+            }
+
+            let path = std::slice::from_raw_parts(sun.sun_path.as_ptr() as *const u8,
+                                                   self.len as usize - base);
+            let end = path.iter().position(|&b| b == 0).unwrap_or(path.len());
+            Some(PathBuf::from(OsStr::from_bytes(&path[..end])))
         }
     }
 
+    /// Return the address family stored in the internal buffer (e.g. `libc::AF_INET`)
+    pub fn family(&self) -> libc::sa_family_t
+    {
+        self.storage.ss_family
+    }
+
+    /// Return the length of the address
+    ///
+    /// This is not derived from `.family()`, nor is it `.capacity()`: it is either the length passed
+    /// to `.from_raw()`/`.from_raw_parts()`, or explicitly set with `.set_len()`.
+    ///
+    pub fn len(&self) -> libc::socklen_t
+    {
+        self.len
+    }
+
+    /// Set the length of the address
+    ///
+    /// Use this after filling the buffer returned by `.as_mut_ptr()` through a raw syscall (e.g.
+    /// `recvfrom`, `getsockname`) to record how many bytes it actually wrote.
+    ///
+    pub fn set_len(&mut self, len: libc::socklen_t)
+    {
+        self.len = len;
+    }
+
     /// Return the size of the internal buffer
     pub fn capacity(&self) -> usize
     {
-        std::mem::size_of::<libc::sockaddr_in6>()
+        std::mem::size_of::<libc::sockaddr_storage>()
     }
 
     /// Get a pointer to the internal buffer
     pub fn as_ptr(&self) -> *const libc::sockaddr {
-        &self.sa6 as *const _ as *const _
+        &self.storage as *const _ as *const _
     }
 
     /// Get a mutable pointer to the internal buffer
     pub fn as_mut_ptr(&mut self) -> *mut libc::sockaddr {
-        &mut self.sa6 as *mut _ as *mut _
+        &mut self.storage as *mut _ as *mut _
     }
 
 }
@@ -109,12 +153,13 @@ impl RawSocketAddr {
 impl AsRef<[u8]> for RawSocketAddr
 {
     /// Get the internal buffer as a byte slice
-    /// 
-    /// Note: the actual length of slice depends on the value of `.sa_family` (see `.len()`)
-    /// 
+    ///
+    /// Note: this always returns the full internal buffer; see `.len()` for the actual address
+    /// length.
+    ///
     fn as_ref(&self) -> &[u8] {
         unsafe {
-            std::slice::from_raw_parts(&self.sa6 as *const _ as *const u8, self.len())
+            std::slice::from_raw_parts(&self.storage as *const _ as *const u8, self.capacity())
         }
     }
 }
@@ -124,7 +169,7 @@ impl AsMut<[u8]> for RawSocketAddr
     /// Get the internal buffer as a mutable slice
     fn as_mut(&mut self) -> &mut[u8] {
         unsafe {
-            std::slice::from_raw_parts_mut(&mut self.sa6 as *mut _ as *mut u8, self.capacity())
+            std::slice::from_raw_parts_mut(&mut self.storage as *mut _ as *mut u8, self.capacity())
         }
     }
 }
@@ -132,17 +177,35 @@ impl AsMut<[u8]> for RawSocketAddr
 impl Into<Option<SocketAddr>> for RawSocketAddr
 {
     /// Attempt to convert the internal buffer into a `std::net::SocketAddr` object
-    /// 
+    ///
     /// The internal buffer is assumed to be a `libc::sockaddr`.
-    /// 
-    /// If the value of `.sa_family` resolves to `AF_INET` or `AF_INET6` then the buffer is
+    ///
+    /// If the value of `.family()` resolves to `AF_INET` or `AF_INET6` then the buffer is
     /// converted into `SocketAddr`, otherwise the function returns None.
-    /// 
+    ///
+    /// Note: this converts field-by-field rather than transmuting the raw `sockaddr_in`/
+    /// `sockaddr_in6` bytes, since `std::net::SocketAddrV4`/`V6` have no documented layout
+    /// compatible with the libc structs.
+    ///
     fn into(self) -> Option<SocketAddr>
     {
-        unsafe { match self.sa6.sin6_family as i32 {
-                libc::AF_INET   => Some(SocketAddr::V4(*(self.as_ptr() as *const _))),
-                libc::AF_INET6  => Some(SocketAddr::V6(*(self.as_ptr() as *const _))),
+        unsafe { match self.family() as libc::c_int {
+                libc::AF_INET   => {
+                    let sin = &*(self.as_ptr() as *const libc::sockaddr_in);
+                    Some(SocketAddr::V4(SocketAddrV4::new(
+                        Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)),
+                        u16::from_be(sin.sin_port),
+                    )))
+                },
+                libc::AF_INET6  => {
+                    let sin6 = &*(self.as_ptr() as *const libc::sockaddr_in6);
+                    Some(SocketAddr::V6(SocketAddrV6::new(
+                        Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                        u16::from_be(sin6.sin6_port),
+                        sin6.sin6_flowinfo,
+                        sin6.sin6_scope_id,
+                    )))
+                },
                 _ => None
         }}
     }
@@ -150,9 +213,37 @@ impl Into<Option<SocketAddr>> for RawSocketAddr
 
 impl From<SocketAddr> for RawSocketAddr
 {
+    /// Build a raw `sockaddr_in`/`sockaddr_in6` from a `std::net::SocketAddr`, field-by-field
+    /// (see the note on the `Into<Option<SocketAddr>>` impl above for why).
     fn from(addr: SocketAddr) -> Self
     {
-        Self::from(Some(&addr))
+        let mut raw = RawSocketAddr::new();
+        unsafe {
+            match addr {
+                SocketAddr::V4(addr) => {
+                    let sin = libc::sockaddr_in {
+                        sin_family: libc::AF_INET as libc::sa_family_t,
+                        sin_port:   addr.port().to_be(),
+                        sin_addr:   libc::in_addr{s_addr: u32::from(*addr.ip()).to_be()},
+                        sin_zero:   std::mem::zeroed(),
+                    };
+                    *(raw.as_mut_ptr() as *mut libc::sockaddr_in) = sin;
+                    raw.len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+                },
+                SocketAddr::V6(addr) => {
+                    let sin6 = libc::sockaddr_in6 {
+                        sin6_family:   libc::AF_INET6 as libc::sa_family_t,
+                        sin6_port:     addr.port().to_be(),
+                        sin6_flowinfo: addr.flowinfo(),
+                        sin6_addr:     libc::in6_addr{s6_addr: addr.ip().octets()},
+                        sin6_scope_id: addr.scope_id(),
+                    };
+                    *(raw.as_mut_ptr() as *mut libc::sockaddr_in6) = sin6;
+                    raw.len = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+                },
+            }
+        }
+        raw
     }
 }
 
@@ -174,7 +265,7 @@ mod tests {
         let ptr = raw as *mut _ as usize;
         let buf = raw.as_mut();
         assert_eq!(buf.as_mut_ptr(), ptr as *mut _);
-        assert_eq!(buf.len(), std::mem::size_of::<libc::sockaddr_in6>());
+        assert_eq!(buf.len(), std::mem::size_of::<libc::sockaddr_storage>());
     }
 
     #[test]
@@ -182,7 +273,8 @@ mod tests {
         let mut raw = RawSocketAddr::new();
         assert_eq!(raw.as_ptr(), &raw as *const _ as *const _);
         assert_eq!(raw.as_mut_ptr(), &mut raw as *mut _ as *mut _);
-        assert_eq!(raw.capacity(), std::mem::size_of::<libc::sockaddr_in6>());
+        assert_eq!(raw.capacity(), std::mem::size_of::<libc::sockaddr_storage>());
+        assert_eq!(raw.len(), 0);
     }
 
     #[test]
@@ -191,13 +283,13 @@ mod tests {
         {
             let sl = raw.as_ref();
             assert_eq!(sl.as_ptr(), &raw as *const _ as *const _);
-            assert_eq!(sl.len(), 0);
+            assert_eq!(sl.len(), std::mem::size_of::<libc::sockaddr_storage>());
         }
         {
             let ptr = &mut raw as *mut _ as *mut _;
             let sl = raw.as_mut();
             assert_eq!(sl.as_mut_ptr(), ptr);
-            assert_eq!(sl.len(), std::mem::size_of::<libc::sockaddr_in6>());
+            assert_eq!(sl.len(), std::mem::size_of::<libc::sockaddr_storage>());
         }
     }
 
@@ -214,15 +306,15 @@ mod tests {
             };
             let mut raw = RawSocketAddr::from_raw_parts(&sa as *const _ as *const u8,
                                                     std::mem::size_of_val(&sa));
-            assert_eq!(raw.len(),       std::mem::size_of::<libc::sockaddr_in>());
-            assert_eq!(raw.capacity(),  std::mem::size_of::<libc::sockaddr_in6>());
+            assert_eq!(raw.len(),       std::mem::size_of_val(&sa) as libc::socklen_t);
+            assert_eq!(raw.capacity(),  std::mem::size_of::<libc::sockaddr_storage>());
             assert_eq!(raw.into_addr(), Some(addr));
             assert_eq!(RawSocketAddr::from(Some(&addr)).into_addr(), Some(addr));
             {
                 let buf = raw.as_ref();
                 assert_eq!(buf.as_ptr(), &raw as *const _ as *const _);
-                assert_eq!(buf.len(), std::mem::size_of_val(&sa));
-            } 
+                assert_eq!(buf.len(), std::mem::size_of::<libc::sockaddr_storage>());
+            }
             check_as_mut(&mut raw);
         }
     }
@@ -243,30 +335,45 @@ mod tests {
             };
             let mut raw = RawSocketAddr::from_raw_parts(&sa as *const _ as *const u8,
                                                     std::mem::size_of_val(&sa));
-            assert_eq!(raw.len(),       std::mem::size_of::<libc::sockaddr_in6>());
-            assert_eq!(raw.capacity(),  std::mem::size_of::<libc::sockaddr_in6>());
+            assert_eq!(raw.len(),       std::mem::size_of_val(&sa) as libc::socklen_t);
+            assert_eq!(raw.capacity(),  std::mem::size_of::<libc::sockaddr_storage>());
             assert_eq!(raw.into_addr(), Some(addr));
             assert_eq!(RawSocketAddr::from(Some(&addr)).into_addr(), Some(addr));
             {
                 let buf = raw.as_ref();
                 assert_eq!(buf.as_ptr(), &raw as *const _ as *const _);
-                assert_eq!(buf.len(), std::mem::size_of_val(&sa));
+                assert_eq!(buf.len(), std::mem::size_of::<libc::sockaddr_storage>());
             }
             check_as_mut(&mut raw);
         }
     }
 
+    #[test]
+    fn rawsocketaddr_unix()
+    {
+        unsafe {
+            let mut sun: libc::sockaddr_un = std::mem::zeroed();
+            sun.sun_family = libc::AF_UNIX as u16;
+            let path = b"/tmp/udp_sas.sock";
+            (&mut sun.sun_path[..path.len()] as *mut [i8]).cast::<u8>()
+                .copy_from(path.as_ptr(), path.len());
+
+            let base = &sun.sun_path as *const _ as usize - &sun as *const _ as usize;
+            let mut raw = RawSocketAddr::from_raw_parts(&sun as *const _ as *const u8,
+                                                         base + path.len());
+            assert_eq!(raw.family() as i32, libc::AF_UNIX);
+            assert_eq!(raw.into_addr(), None);
+            assert_eq!(raw.into_path(), Some(PathBuf::from("/tmp/udp_sas.sock")));
+            check_as_mut(&mut raw);
+        }
+    }
+
     #[test]
     fn rawsocketaddr_other()
     {
         fn check(raw: &mut RawSocketAddr) {
             assert_eq!(raw.into_addr(), None);
-            {
-                let buf = raw.as_ref();
-                assert_eq!(buf.len(), 0);
-                assert_eq!(raw.len(), 0);
-                assert_eq!(raw.capacity(), std::mem::size_of::<libc::sockaddr_in6>());
-            }
+            assert_eq!(raw.into_path(), None);
             check_as_mut(raw);
         };
 
@@ -278,4 +385,3 @@ mod tests {
         }
     }
 }
-
@@ -0,0 +1,608 @@
+//! Unix backend: `recvmsg`/`sendmsg` wrappers enabling per-datagram source
+//! address (and interface) selection through the `IP_PKTINFO`/
+//! `IPV6_RECVPKTINFO` ancillary data.
+
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::RawFd;
+
+use os_socketaddr::OsSocketAddr;
+
+macro_rules! try_io {
+    ($x:expr) => {
+        match $x {
+            -1 => {return Err(io::Error::last_os_error());},
+            x  => x
+            }}
+}
+
+fn getsockopt<T>(socket: RawFd, level: libc::c_int, name: libc::c_int, value: &mut T)
+    -> io::Result<libc::socklen_t>
+{
+    unsafe {
+        let mut len = std::mem::size_of::<T>() as libc::socklen_t;
+        try_io!(libc::getsockopt(socket, level, name,
+                                 value as *mut T as *mut libc::c_void,
+                                 &mut len));
+        Ok(len)
+    }
+}
+fn setsockopt<T>(socket: RawFd, level: libc::c_int, name: libc::c_int, value: &T)
+    -> io::Result<()>
+{
+    unsafe {
+        try_io!(libc::setsockopt(socket, level, name,
+                                 value as *const T as *const libc::c_void,
+                                 std::mem::size_of::<T>() as libc::socklen_t));
+        Ok(())
+    }
+}
+
+/// enable IP_PKTINFO/IPV6_RECVPKTINFO on a socket
+pub fn set_pktinfo(socket: RawFd) -> io::Result<()>
+{
+    unsafe {
+        let mut domain = libc::c_int::default();
+        getsockopt(socket, libc::SOL_SOCKET, libc::SO_DOMAIN, &mut domain)?;
+
+        let (level, option) = match domain {
+            libc::AF_INET  => (libc::IPPROTO_IP,   libc::IP_PKTINFO),
+            libc::AF_INET6 => (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO),
+            _ => { return Err(io::Error::new(io::ErrorKind::Other, "not an inet socket")); }
+        };
+
+        setsockopt(socket, level, option, &(1 as libc::c_int))
+    }
+}
+
+/// Scratch space for a single `in_pktinfo`/`in6_pktinfo` ancillary message.
+///
+/// `in6_pktinfo` is the bigger of the two, so a generously rounded buffer
+/// comfortably holds either one plus its `cmsghdr`.
+const PKTINFO_CMSG_SPACE: usize = 64;
+
+/// A `PKTINFO_CMSG_SPACE`-sized buffer, aligned like a `cmsghdr`.
+///
+/// `CMSG_FIRSTHDR`/`CMSG_DATA` and the `ptr::write` in [`write_cmsg`] all
+/// require their target to be suitably aligned for a `cmsghdr` (and the
+/// `in_pktinfo`/`in6_pktinfo` that follows it); a bare `[u8; N]` has no
+/// alignment guarantee beyond 1, so it's wrapped here instead.
+#[derive(Clone, Copy)]
+#[repr(align(8))]
+struct CmsgBuf([u8; PKTINFO_CMSG_SPACE]);
+
+impl CmsgBuf {
+    fn new() -> Self { CmsgBuf([0u8; PKTINFO_CMSG_SPACE]) }
+}
+
+impl std::ops::Deref for CmsgBuf {
+    type Target = [u8; PKTINFO_CMSG_SPACE];
+    fn deref(&self) -> &Self::Target { &self.0 }
+}
+
+impl std::ops::DerefMut for CmsgBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target { &mut self.0 }
+}
+
+/// Build the `msghdr.msg_control` payload selecting `local`/`ifindex` as the
+/// outgoing packet's source address and interface.
+///
+/// Returns the number of bytes written into `cmsg_buf`, or `0` if there is
+/// nothing to set (in which case the kernel picks the source the usual way).
+fn fill_pktinfo(cmsg_buf: &mut CmsgBuf, socket: RawFd,
+                local: Option<&IpAddr>, ifindex: Option<u32>)
+    -> io::Result<libc::socklen_t>
+{
+    if local.is_none() && ifindex.is_none() {
+        return Ok(0);
+    }
+
+    let family = match local {
+        Some(IpAddr::V4(_)) => libc::AF_INET,
+        Some(IpAddr::V6(_)) => libc::AF_INET6,
+        None => {
+            let mut domain = libc::c_int::default();
+            getsockopt(socket, libc::SOL_SOCKET, libc::SO_DOMAIN, &mut domain)?;
+            domain
+        }
+    };
+
+    let len = unsafe {
+        match family {
+            libc::AF_INET6 => {
+                let mut info: libc::in6_pktinfo = mem::zeroed();
+                if let Some(IpAddr::V6(ip)) = local {
+                    info.ipi6_addr = libc::in6_addr{s6_addr: ip.octets()};
+                }
+                if let Some(idx) = ifindex {
+                    info.ipi6_ifindex = idx as libc::c_uint;
+                }
+                write_cmsg(cmsg_buf, libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, info)
+            },
+            _ => {
+                let mut info: libc::in_pktinfo = mem::zeroed();
+                if let Some(IpAddr::V4(ip)) = local {
+                    info.ipi_spec_dst = libc::in_addr{s_addr: u32::from(*ip).to_be()};
+                }
+                if let Some(idx) = ifindex {
+                    info.ipi_ifindex = idx as libc::c_int;
+                }
+                write_cmsg(cmsg_buf, libc::IPPROTO_IP, libc::IP_PKTINFO, info)
+            },
+        }
+    };
+
+    Ok(len as libc::socklen_t)
+}
+
+/// Write a single ancillary message of type `(level, ty)` holding `data` at
+/// the start of `cmsg_buf`. Returns the total length of the message
+/// (`CMSG_SPACE`), i.e. the value to pass as `msg_controllen`.
+unsafe fn write_cmsg<T>(cmsg_buf: &mut CmsgBuf, level: libc::c_int, ty: libc::c_int, data: T) -> usize
+{
+    let space = libc::CMSG_SPACE(mem::size_of::<T>() as u32) as usize;
+    assert!(space <= cmsg_buf.len(), "PKTINFO_CMSG_SPACE too small");
+
+    let mut msg: libc::msghdr = mem::zeroed();
+    msg.msg_control    = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    let cmsg = libc::CMSG_FIRSTHDR(&msg);
+    (*cmsg).cmsg_level = level;
+    (*cmsg).cmsg_type  = ty;
+    (*cmsg).cmsg_len   = libc::CMSG_LEN(mem::size_of::<T>() as u32) as _;
+    std::ptr::write(libc::CMSG_DATA(cmsg) as *mut T, data);
+
+    space
+}
+
+/// Walk the ancillary data of a received message looking for `IP_PKTINFO`/
+/// `IPV6_PKTINFO`, returning the local address and interface index it
+/// carries, if any.
+unsafe fn parse_pktinfo(msg: &libc::msghdr) -> (Option<IpAddr>, Option<u32>)
+{
+    let mut local   = None;
+    let mut ifindex = None;
+
+    let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+    while !cmsg.is_null() {
+        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                let info = *(libc::CMSG_DATA(cmsg) as *const libc::in_pktinfo);
+                local   = Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(info.ipi_spec_dst.s_addr))));
+                ifindex = Some(info.ipi_ifindex as u32);
+            },
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                let info = *(libc::CMSG_DATA(cmsg) as *const libc::in6_pktinfo);
+                local   = Some(IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)));
+                ifindex = Some(info.ipi6_ifindex as u32);
+            },
+            _ => {},
+        }
+        cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+    }
+
+    (local, ifindex)
+}
+
+/// Receive a datagram (low-level function)
+///
+/// Parameters
+///
+/// * `buf`: buffer for storing the payload
+///
+/// Returns a tuple containing:
+///
+///   * the size of the payload
+///   * the source socket address (peer)
+///   * the destination ip address (local)
+///   * the interface index the datagram arrived on
+///
+/// Note: the source (peer), destination (local) and interface index may not
+/// be present in the result if the underlying socket does not provide them.
+pub fn recv_sas(socket: RawFd, buf: &mut [u8])
+    -> io::Result<(usize, Option<SocketAddr>, Option<IpAddr>, Option<u32>)>
+{
+    let mut src = OsSocketAddr::new();
+    let mut cmsg_buf = CmsgBuf::new();
+
+    let mut iov = libc::iovec{
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len:  buf.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name       = src.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_namelen    = src.capacity() as libc::socklen_t;
+    msg.msg_iov        = &mut iov;
+    msg.msg_iovlen     = 1;
+    msg.msg_control    = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = cmsg_buf.len() as _;
+
+    let nb = try_io!(unsafe { libc::recvmsg(socket, &mut msg, 0) });
+    let (local, ifindex) = unsafe { parse_pktinfo(&msg) };
+
+    Ok((nb as usize, src.into(), local, ifindex))
+}
+
+/// Send datagram (low-level function)
+///
+/// Return the size of the sent payload
+///
+/// Note: the source (local), destination (target) addresses and outgoing
+/// interface are optional.
+pub fn send_sas_if(socket: RawFd, buf: &[u8], target: Option<&SocketAddr>,
+                    local: Option<&IpAddr>, ifindex: Option<u32>)
+    -> io::Result<usize>
+{
+    let dst: OsSocketAddr = target.map(|a| *a).into();
+    let mut cmsg_buf = CmsgBuf::new();
+    let cmsg_len = fill_pktinfo(&mut cmsg_buf, socket, local, ifindex)?;
+
+    let mut iov = libc::iovec{
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len:  buf.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name    = dst.as_ptr() as *mut libc::c_void;
+    msg.msg_namelen = dst.len() as libc::socklen_t;
+    msg.msg_iov     = &mut iov;
+    msg.msg_iovlen  = 1;
+    if cmsg_len > 0 {
+        msg.msg_control    = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsg_len as _;
+    }
+
+    let nb = try_io!(unsafe { libc::sendmsg(socket, &msg, 0) });
+    Ok(nb as usize)
+}
+
+/// Send datagram (low-level function), without pinning a specific outgoing
+/// interface. See [`send_sas_if`] to also select the interface.
+pub fn send_sas(socket: RawFd, buf: &[u8], target: Option<&SocketAddr>, local: Option<&IpAddr>)
+    -> io::Result<usize>
+{
+    send_sas_if(socket, buf, target, local, None)
+}
+
+/// An opaque, `Clone`-able capture of a received datagram's peer address
+/// together with the pktinfo needed to reply to it.
+///
+/// Produced by [`recv_endpoint`] and consumed by [`send_endpoint`]. Replying
+/// through a `UdpEndpoint` is a single `sendmsg` with no re-conversion
+/// through `std::net` types: the peer's raw `sockaddr` and the outgoing
+/// `in_pktinfo`/`in6_pktinfo` control block (source address and interface)
+/// are captured once, at receive time.
+#[derive(Clone)]
+pub struct UdpEndpoint {
+    peer:     OsSocketAddr,
+    cmsg:     CmsgBuf,
+    cmsg_len: libc::socklen_t,
+}
+
+/// Build the reply `in_pktinfo`/`in6_pktinfo` control block from the one a
+/// datagram was received with: for IPv4, `ipi_spec_dst` — the local address
+/// of the datagram — carries over unchanged as the reply's source; `ipi_addr`
+/// is left zeroed since it is ignored on send. For IPv6 the single
+/// `ipi6_addr` field is already the right shape to reuse as-is. The
+/// interface index carries over unchanged in both cases.
+unsafe fn reply_pktinfo(recv_msg: &libc::msghdr) -> (CmsgBuf, libc::socklen_t)
+{
+    let mut cmsg_buf = CmsgBuf::new();
+    let mut cmsg_len = 0;
+
+    let mut cmsg = libc::CMSG_FIRSTHDR(recv_msg);
+    while !cmsg.is_null() {
+        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                let info = *(libc::CMSG_DATA(cmsg) as *const libc::in_pktinfo);
+                let reply = libc::in_pktinfo{
+                    ipi_ifindex:  info.ipi_ifindex,
+                    ipi_spec_dst: info.ipi_spec_dst,
+                    ipi_addr:     mem::zeroed(),
+                };
+                cmsg_len = write_cmsg(&mut cmsg_buf, libc::IPPROTO_IP, libc::IP_PKTINFO, reply) as libc::socklen_t;
+            },
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                let info = *(libc::CMSG_DATA(cmsg) as *const libc::in6_pktinfo);
+                cmsg_len = write_cmsg(&mut cmsg_buf, libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, info) as libc::socklen_t;
+            },
+            _ => {},
+        }
+        cmsg = libc::CMSG_NXTHDR(recv_msg, cmsg);
+    }
+
+    (cmsg_buf, cmsg_len)
+}
+
+/// Receive a datagram, capturing its peer address and reply pktinfo as a
+/// [`UdpEndpoint`] rather than decoding them into `std::net` types.
+///
+/// Returns the size of the payload and the `UdpEndpoint`, which can later be
+/// handed to [`send_endpoint`] to reply without reconstructing the address.
+pub fn recv_endpoint(socket: RawFd, buf: &mut [u8]) -> io::Result<(usize, UdpEndpoint)>
+{
+    let mut peer = OsSocketAddr::new();
+    let mut recv_cmsg = CmsgBuf::new();
+
+    let mut iov = libc::iovec{
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len:  buf.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name       = peer.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_namelen    = peer.capacity() as libc::socklen_t;
+    msg.msg_iov        = &mut iov;
+    msg.msg_iovlen     = 1;
+    msg.msg_control    = recv_cmsg.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = recv_cmsg.len() as _;
+
+    let nb = try_io!(unsafe { libc::recvmsg(socket, &mut msg, 0) });
+    let (cmsg, cmsg_len) = unsafe { reply_pktinfo(&msg) };
+
+    Ok((nb as usize, UdpEndpoint{peer, cmsg, cmsg_len}))
+}
+
+/// Send a datagram to the peer captured by `endpoint`, using the source
+/// address and interface the original datagram was received on.
+pub fn send_endpoint(socket: RawFd, endpoint: &UdpEndpoint, buf: &[u8]) -> io::Result<usize>
+{
+    let mut iov = libc::iovec{
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len:  buf.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name    = endpoint.peer.as_ptr() as *mut libc::c_void;
+    msg.msg_namelen = endpoint.peer.len() as libc::socklen_t;
+    msg.msg_iov     = &mut iov;
+    msg.msg_iovlen  = 1;
+    if endpoint.cmsg_len > 0 {
+        msg.msg_control    = endpoint.cmsg.as_ptr() as *mut libc::c_void;
+        msg.msg_controllen = endpoint.cmsg_len as _;
+    }
+
+    let nb = try_io!(unsafe { libc::sendmsg(socket, &msg, 0) });
+    Ok(nb as usize)
+}
+
+/// The result of a single datagram within a [`recv_sas_batch`] call: the
+/// payload size, source (peer) address, destination (local) address and
+/// interface index, as returned by [`recv_sas`].
+pub type BatchRecvResult = (usize, Option<SocketAddr>, Option<IpAddr>, Option<u32>);
+
+/// A single outgoing datagram within a [`send_sas_batch`] call: the
+/// payload, destination (target) address and source (local) address, as
+/// accepted by [`send_sas`].
+pub type BatchSendItem<'a> = (&'a [u8], Option<SocketAddr>, Option<IpAddr>);
+
+/// Receive up to `bufs.len()` datagrams in as few syscalls as possible.
+///
+/// `results[i]` is filled in for every datagram received; the return value
+/// is the number of datagrams actually received, which may be less than
+/// `bufs.len()`. `bufs` and `results` must have the same length.
+///
+/// On Linux/Android this is backed by a single `recvmmsg(2)` call. On other
+/// Unixes, which lack `recvmmsg`, it falls back to a loop over [`recv_sas`]
+/// that stops as soon as a receive would block.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn recv_sas_batch(socket: RawFd, bufs: &mut [&mut [u8]], results: &mut [BatchRecvResult])
+    -> io::Result<usize>
+{
+    assert_eq!(bufs.len(), results.len());
+    let n = bufs.len();
+
+    let mut srcs      = vec![OsSocketAddr::new(); n];
+    let mut cmsg_bufs = vec![CmsgBuf::new(); n];
+    let mut iovs: Vec<libc::iovec> = bufs.iter_mut().map(|buf| libc::iovec{
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len:  buf.len(),
+    }).collect();
+
+    let mut hdrs: Vec<libc::mmsghdr> = (0..n).map(|i| unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name       = srcs[i].as_mut_ptr() as *mut libc::c_void;
+        msg.msg_namelen    = srcs[i].capacity() as libc::socklen_t;
+        msg.msg_iov        = &mut iovs[i];
+        msg.msg_iovlen     = 1;
+        msg.msg_control    = cmsg_bufs[i].as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsg_bufs[i].len() as _;
+        libc::mmsghdr{msg_hdr: msg, msg_len: 0}
+    }).collect();
+
+    let nb = try_io!(unsafe {
+        libc::recvmmsg(socket, hdrs.as_mut_ptr(), n as libc::c_uint, 0, std::ptr::null_mut())
+    });
+
+    for i in 0..nb as usize {
+        let (local, ifindex) = unsafe { parse_pktinfo(&hdrs[i].msg_hdr) };
+        results[i] = (hdrs[i].msg_len as usize, srcs[i].clone().into(), local, ifindex);
+    }
+
+    Ok(nb as usize)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn recv_sas_batch(socket: RawFd, bufs: &mut [&mut [u8]], results: &mut [BatchRecvResult])
+    -> io::Result<usize>
+{
+    assert_eq!(bufs.len(), results.len());
+
+    let mut n = 0;
+    for (buf, result) in bufs.iter_mut().zip(results.iter_mut()) {
+        match recv_sas(socket, buf) {
+            Ok(r) => { *result = r; n += 1; },
+            Err(ref e) if n > 0 && e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Send `msgs.len()` datagrams in as few syscalls as possible.
+///
+/// Returns the number of datagrams actually sent, which may be less than
+/// `msgs.len()` if the kernel only accepted a partial batch.
+///
+/// On Linux/Android this is backed by a single `sendmmsg(2)` call. On other
+/// Unixes, which lack `sendmmsg`, it falls back to a loop over [`send_sas`].
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn send_sas_batch(socket: RawFd, msgs: &[BatchSendItem]) -> io::Result<usize>
+{
+    let n = msgs.len();
+
+    let dsts: Vec<OsSocketAddr> = msgs.iter().map(|&(_, target, _)| target.into()).collect();
+    let mut cmsg_bufs = vec![CmsgBuf::new(); n];
+    let mut cmsg_lens = Vec::with_capacity(n);
+    for (i, &(_, _, local)) in msgs.iter().enumerate() {
+        cmsg_lens.push(fill_pktinfo(&mut cmsg_bufs[i], socket, local.as_ref(), None)?);
+    }
+
+    let mut iovs: Vec<libc::iovec> = msgs.iter().map(|&(buf, _, _)| libc::iovec{
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len:  buf.len(),
+    }).collect();
+
+    let mut hdrs: Vec<libc::mmsghdr> = (0..n).map(|i| unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name    = dsts[i].as_ptr() as *mut libc::c_void;
+        msg.msg_namelen = dsts[i].len() as libc::socklen_t;
+        msg.msg_iov     = &mut iovs[i];
+        msg.msg_iovlen  = 1;
+        if cmsg_lens[i] > 0 {
+            msg.msg_control    = cmsg_bufs[i].as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = cmsg_lens[i] as _;
+        }
+        libc::mmsghdr{msg_hdr: msg, msg_len: 0}
+    }).collect();
+
+    let nb = try_io!(unsafe {
+        libc::sendmmsg(socket, hdrs.as_mut_ptr(), n as libc::c_uint, 0)
+    });
+
+    Ok(nb as usize)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn send_sas_batch(socket: RawFd, msgs: &[BatchSendItem]) -> io::Result<usize>
+{
+    for &(buf, target, local) in msgs {
+        send_sas(socket, buf, target.as_ref(), local.as_ref())?;
+    }
+    Ok(msgs.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    fn bind_sas(addr: &str) -> UdpSocket {
+        let sock = UdpSocket::bind(addr).unwrap();
+        set_pktinfo(sock.as_raw_fd()).unwrap();
+        sock
+    }
+
+    #[test]
+    fn recv_sas_reports_spec_dst_as_local_and_ifindex()
+    {
+        let srv = bind_sas("127.0.0.1:0");
+        let srv_addr = srv.local_addr().unwrap();
+        let cli = bind_sas("127.0.0.1:0");
+        let cli_addr = cli.local_addr().unwrap();
+
+        let msg = b"What do you get if you multiply six by nine?";
+        let nb = send_sas(cli.as_raw_fd(), msg, Some(&srv_addr), Some(&cli_addr.ip())).unwrap();
+        assert_eq!(nb, msg.len());
+
+        let mut buf = [0u8; 128];
+        let (nb, peer, local, ifindex) = recv_sas(srv.as_raw_fd(), &mut buf).unwrap();
+        assert_eq!(&buf[..nb], msg);
+        assert_eq!(peer, Some(cli_addr));
+        // the loopback interface only has one address, so ipi_spec_dst and
+        // ipi_addr happen to coincide here; what matters is that we read the
+        // field meant for replies rather than the IP header's destination.
+        assert_eq!(local, Some(srv_addr.ip()));
+        assert!(ifindex.is_some());
+    }
+
+    #[test]
+    fn send_sas_if_pins_the_outgoing_interface()
+    {
+        let srv = bind_sas("127.0.0.1:0");
+        let srv_addr = srv.local_addr().unwrap();
+        let cli = bind_sas("127.0.0.1:0");
+        let cli_addr = cli.local_addr().unwrap();
+
+        let mut buf = [0u8; 128];
+        send_sas(cli.as_raw_fd(), b"probe", Some(&srv_addr), None).unwrap();
+        let (_, _, _, ifindex) = recv_sas(srv.as_raw_fd(), &mut buf).unwrap();
+        let ifindex = ifindex.expect("loopback should report an ifindex");
+
+        let msg = b"Forty-two";
+        let nb = send_sas_if(cli.as_raw_fd(), msg, Some(&srv_addr), Some(&cli_addr.ip()), Some(ifindex)).unwrap();
+        assert_eq!(nb, msg.len());
+
+        let (nb, peer, local, _) = recv_sas(srv.as_raw_fd(), &mut buf).unwrap();
+        assert_eq!(&buf[..nb], msg);
+        assert_eq!(peer, Some(cli_addr));
+        assert_eq!(local, Some(srv_addr.ip()));
+    }
+
+    #[test]
+    fn recv_endpoint_replies_from_spec_dst()
+    {
+        let srv = bind_sas("127.0.0.1:0");
+        let srv_addr = srv.local_addr().unwrap();
+        let cli = bind_sas("127.0.0.1:0");
+        let cli_addr = cli.local_addr().unwrap();
+
+        let msg1 = b"What do you get if you multiply six by nine?";
+        send_sas(cli.as_raw_fd(), msg1, Some(&srv_addr), Some(&cli_addr.ip())).unwrap();
+
+        let mut buf = [0u8; 128];
+        let (nb, endpoint) = recv_endpoint(srv.as_raw_fd(), &mut buf).unwrap();
+        assert_eq!(&buf[..nb], msg1);
+
+        let msg2 = b"Forty-two";
+        let nb = send_endpoint(srv.as_raw_fd(), &endpoint, msg2).unwrap();
+        assert_eq!(nb, msg2.len());
+
+        let (nb, peer, local, _) = recv_sas(cli.as_raw_fd(), &mut buf).unwrap();
+        assert_eq!(&buf[..nb], msg2);
+        assert_eq!(peer, Some(srv_addr));
+        assert_eq!(local, Some(cli_addr.ip()));
+    }
+
+    #[test]
+    fn batch_roundtrip()
+    {
+        let srv = bind_sas("127.0.0.1:0");
+        let srv_addr = srv.local_addr().unwrap();
+        let cli = bind_sas("127.0.0.1:0");
+        let cli_addr = cli.local_addr().unwrap();
+
+        let msgs: [&[u8]; 2] = [b"six", b"nine"];
+        let items: Vec<BatchSendItem> = msgs.iter()
+            .map(|&m| (m, Some(srv_addr), Some(cli_addr.ip())))
+            .collect();
+        let sent = send_sas_batch(cli.as_raw_fd(), &items).unwrap();
+        assert_eq!(sent, msgs.len());
+
+        let mut bufs = [[0u8; 128], [0u8; 128]];
+        let mut buf_refs: Vec<&mut [u8]> = bufs.iter_mut().map(|b| &mut b[..]).collect();
+        let mut results: Vec<BatchRecvResult> = vec![Default::default(); msgs.len()];
+        let received = recv_sas_batch(srv.as_raw_fd(), &mut buf_refs, &mut results).unwrap();
+        assert_eq!(received, msgs.len());
+
+        for (i, &(nb, peer, local, _)) in results.iter().enumerate() {
+            assert_eq!(&buf_refs[i][..nb], msgs[i]);
+            assert_eq!(peer, Some(cli_addr));
+            assert_eq!(local, Some(srv_addr.ip()));
+        }
+    }
+}
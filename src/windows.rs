@@ -0,0 +1,338 @@
+//! Windows backend: `WSARecvMsg`/`WSASendMsg` wrappers enabling per-datagram
+//! source address (and interface) selection through the `IP_PKTINFO`/
+//! `IPV6_PKTINFO` ancillary data.
+//!
+//! This mirrors `unix.rs`: same public functions and `UdpEndpoint` type, but
+//! built on Winsock's `WSAMSG`/`WSABUF` instead of `msghdr`/`iovec`, and keyed
+//! on `AsRawSocket` instead of `AsRawFd`. `WSASendMsg` is a regular exported
+//! function, but `WSARecvMsg` is only available as a per-socket extension
+//! function, so its pointer has to be looked up with `WSAIoctl` first.
+
+extern crate winapi;
+
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::windows::io::RawSocket;
+use std::ptr;
+
+use os_socketaddr::OsSocketAddr;
+
+use self::winapi::shared::minwindef::{DWORD, LPVOID};
+use self::winapi::shared::ws2def::{AF_INET, AF_INET6, IPPROTO_IP, IPPROTO_IPV6, SOCKADDR};
+use self::winapi::shared::ws2ipdef::{IN6_PKTINFO, IN_PKTINFO, IP_PKTINFO, IPV6_PKTINFO};
+use self::winapi::um::mswsock::{LPFN_WSARECVMSG, WSAID_WSARECVMSG, SIO_GET_EXTENSION_FUNCTION_POINTER};
+use self::winapi::um::winsock2::{
+    self, SOCKET, SOCKET_ERROR, WSABUF, WSACMSGHDR, WSAMSG,
+};
+
+macro_rules! try_io {
+    ($x:expr) => {
+        match $x {
+            SOCKET_ERROR => { return Err(io::Error::last_os_error()); },
+            x => x,
+            }}
+}
+
+fn setsockopt<T>(socket: SOCKET, level: i32, name: i32, value: &T) -> io::Result<()>
+{
+    unsafe {
+        try_io!(winsock2::setsockopt(socket, level, name,
+                                     value as *const T as *const i8,
+                                     mem::size_of::<T>() as i32));
+        Ok(())
+    }
+}
+
+/// enable IP_PKTINFO/IPV6_PKTINFO on a socket
+pub fn set_pktinfo(socket: RawSocket) -> io::Result<()>
+{
+    let socket = socket as SOCKET;
+    // there is no portable way to read back a socket's address family on Windows, so just
+    // enable both options; the one that does not apply to this socket's family is ignored.
+    let v4 = setsockopt(socket, IPPROTO_IP as i32,   IP_PKTINFO as i32,   &(1 as i32));
+    let v6 = setsockopt(socket, IPPROTO_IPV6 as i32, IPV6_PKTINFO as i32, &(1 as i32));
+    v4.or(v6)
+}
+
+/// Scratch space for a single `IN_PKTINFO`/`IN6_PKTINFO` ancillary message.
+const PKTINFO_CMSG_SPACE: usize = 64;
+
+unsafe fn wsa_cmsg_space(len: usize) -> usize
+{
+    let hdrlen = mem::size_of::<WSACMSGHDR>();
+    let align  = mem::size_of::<usize>();
+    hdrlen + ((len + align - 1) / align) * align
+}
+
+unsafe fn wsa_cmsg_data(cmsg: *mut WSACMSGHDR) -> *mut u8
+{
+    (cmsg as *mut u8).add(mem::size_of::<WSACMSGHDR>())
+}
+
+/// Build the `WSAMSG.Control` payload selecting `local`/`ifindex` as the
+/// outgoing packet's source address and interface.
+///
+/// Returns the number of bytes written into `cmsg_buf`, or `0` if there is
+/// nothing to set (in which case Windows picks the source the usual way).
+fn fill_pktinfo(cmsg_buf: &mut [u8; PKTINFO_CMSG_SPACE], local: Option<&IpAddr>, ifindex: Option<u32>) -> u32
+{
+    if local.is_none() && ifindex.is_none() {
+        return 0;
+    }
+
+    let family = match local {
+        Some(IpAddr::V6(_)) => AF_INET6,
+        _                   => AF_INET,
+    };
+
+    unsafe {
+        let cmsg = cmsg_buf.as_mut_ptr() as *mut WSACMSGHDR;
+        let len  = if family == AF_INET6 {
+            let mut info: IN6_PKTINFO = mem::zeroed();
+            if let Some(IpAddr::V6(ip)) = local {
+                info.ipi6_addr.u.Byte_mut().copy_from_slice(&ip.octets());
+            }
+            if let Some(idx) = ifindex {
+                info.ipi6_ifindex = idx;
+            }
+            (*cmsg).cmsg_level = IPPROTO_IPV6 as i32;
+            (*cmsg).cmsg_type  = IPV6_PKTINFO;
+            (*cmsg).cmsg_len   = wsa_cmsg_space(mem::size_of::<IN6_PKTINFO>());
+            ptr::write(wsa_cmsg_data(cmsg) as *mut IN6_PKTINFO, info);
+            wsa_cmsg_space(mem::size_of::<IN6_PKTINFO>())
+        } else {
+            let mut info: IN_PKTINFO = mem::zeroed();
+            if let Some(IpAddr::V4(ip)) = local {
+                info.ipi_addr.S_un.S_addr(u32::from(*ip).to_be());
+            }
+            if let Some(idx) = ifindex {
+                info.ipi_ifindex = idx;
+            }
+            (*cmsg).cmsg_level = IPPROTO_IP as i32;
+            (*cmsg).cmsg_type  = IP_PKTINFO;
+            (*cmsg).cmsg_len   = wsa_cmsg_space(mem::size_of::<IN_PKTINFO>());
+            ptr::write(wsa_cmsg_data(cmsg) as *mut IN_PKTINFO, info);
+            wsa_cmsg_space(mem::size_of::<IN_PKTINFO>())
+        };
+        len as u32
+    }
+}
+
+/// Walk the ancillary data of a received `WSAMSG` looking for `IP_PKTINFO`/
+/// `IPV6_PKTINFO`, returning the local address and interface index it
+/// carries, if any.
+unsafe fn parse_pktinfo(msg: &WSAMSG) -> (Option<IpAddr>, Option<u32>)
+{
+    let mut local   = None;
+    let mut ifindex = None;
+
+    let mut remaining = msg.Control.len as isize;
+    let mut cmsg = msg.Control.buf as *mut WSACMSGHDR;
+    while remaining as usize >= mem::size_of::<WSACMSGHDR>() {
+        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+            (l, IP_PKTINFO) if l == IPPROTO_IP as i32 => {
+                let info = *(wsa_cmsg_data(cmsg) as *const IN_PKTINFO);
+                local   = Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(info.ipi_addr.S_un.S_addr()))));
+                ifindex = Some(info.ipi_ifindex);
+            },
+            (l, IPV6_PKTINFO) if l == IPPROTO_IPV6 as i32 => {
+                let info = *(wsa_cmsg_data(cmsg) as *const IN6_PKTINFO);
+                local   = Some(IpAddr::V6(Ipv6Addr::from(*info.ipi6_addr.u.Byte())));
+                ifindex = Some(info.ipi6_ifindex);
+            },
+            _ => {},
+        }
+        let advance = (*cmsg).cmsg_len as isize;
+        cmsg       = (cmsg as *mut u8).offset(advance) as *mut WSACMSGHDR;
+        remaining -= advance;
+    }
+
+    (local, ifindex)
+}
+
+/// Look up the per-socket `WSARecvMsg` extension function pointer.
+unsafe fn get_wsarecvmsg(socket: SOCKET) -> io::Result<LPFN_WSARECVMSG>
+{
+    let mut func: LPFN_WSARECVMSG = None;
+    let mut bytes: DWORD = 0;
+
+    try_io!(winsock2::WSAIoctl(
+        socket,
+        SIO_GET_EXTENSION_FUNCTION_POINTER,
+        &WSAID_WSARECVMSG as *const _ as LPVOID,
+        mem::size_of_val(&WSAID_WSARECVMSG) as DWORD,
+        &mut func as *mut _ as LPVOID,
+        mem::size_of_val(&func) as DWORD,
+        &mut bytes,
+        ptr::null_mut(),
+        None));
+
+    Ok(func)
+}
+
+/// Receive a datagram (low-level function)
+///
+/// See [`crate::unix::recv_sas`] for the meaning of the returned tuple.
+pub fn recv_sas(socket: RawSocket, buf: &mut [u8])
+    -> io::Result<(usize, Option<SocketAddr>, Option<IpAddr>, Option<u32>)>
+{
+    let socket = socket as SOCKET;
+    let recvmsg = unsafe { get_wsarecvmsg(socket) }?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "WSARecvMsg is not available"))?;
+
+    let mut src = OsSocketAddr::new();
+    let mut cmsg_buf = [0u8; PKTINFO_CMSG_SPACE];
+    let mut wbuf = WSABUF{len: buf.len() as u32, buf: buf.as_mut_ptr() as *mut i8};
+
+    let mut msg = WSAMSG{
+        name:           src.as_mut_ptr() as *mut SOCKADDR,
+        namelen:        src.capacity() as i32,
+        lpBuffers:      &mut wbuf,
+        dwBufferCount:  1,
+        Control:        WSABUF{len: cmsg_buf.len() as u32, buf: cmsg_buf.as_mut_ptr() as *mut i8},
+        dwFlags:        0,
+    };
+
+    let mut nb: DWORD = 0;
+    try_io!(unsafe { recvmsg(socket, &mut msg, &mut nb, ptr::null_mut(), None) });
+    let (local, ifindex) = unsafe { parse_pktinfo(&msg) };
+
+    Ok((nb as usize, src.into(), local, ifindex))
+}
+
+/// Send datagram (low-level function)
+///
+/// See [`crate::unix::send_sas_if`] for the meaning of the parameters.
+pub fn send_sas_if(socket: RawSocket, buf: &[u8], target: Option<&SocketAddr>,
+                    local: Option<&IpAddr>, ifindex: Option<u32>)
+    -> io::Result<usize>
+{
+    let socket = socket as SOCKET;
+    let dst: OsSocketAddr = target.map(|a| *a).into();
+    let mut cmsg_buf = [0u8; PKTINFO_CMSG_SPACE];
+    let cmsg_len = fill_pktinfo(&mut cmsg_buf, local, ifindex);
+
+    let mut wbuf = WSABUF{len: buf.len() as u32, buf: buf.as_ptr() as *mut i8};
+    let mut msg = WSAMSG{
+        name:           dst.as_ptr() as *mut SOCKADDR,
+        namelen:        dst.len() as i32,
+        lpBuffers:      &mut wbuf,
+        dwBufferCount:  1,
+        Control:        WSABUF{len: cmsg_len, buf: cmsg_buf.as_mut_ptr() as *mut i8},
+        dwFlags:        0,
+    };
+
+    let mut nb: DWORD = 0;
+    try_io!(unsafe { winsock2::WSASendMsg(socket, &mut msg, 0, &mut nb, ptr::null_mut(), None) });
+    Ok(nb as usize)
+}
+
+/// Send datagram (low-level function), without pinning a specific outgoing
+/// interface. See [`send_sas_if`] to also select the interface.
+pub fn send_sas(socket: RawSocket, buf: &[u8], target: Option<&SocketAddr>, local: Option<&IpAddr>)
+    -> io::Result<usize>
+{
+    send_sas_if(socket, buf, target, local, None)
+}
+
+/// An opaque, `Clone`-able capture of a received datagram's peer address
+/// together with the pktinfo needed to reply to it. See
+/// [`crate::unix::UdpEndpoint`] for the Unix counterpart.
+#[derive(Clone)]
+pub struct UdpEndpoint {
+    peer:     OsSocketAddr,
+    cmsg:     [u8; PKTINFO_CMSG_SPACE],
+    cmsg_len: u32,
+}
+
+/// Build the reply `IN_PKTINFO`/`IN6_PKTINFO` control block from the one a
+/// datagram was received with: Windows already reports `ipi_addr`/
+/// `ipi6_addr` as the datagram's destination address, which is exactly the
+/// source address a reply should use, so the block is carried over as-is.
+unsafe fn reply_pktinfo(recv_msg: &WSAMSG) -> ([u8; PKTINFO_CMSG_SPACE], u32)
+{
+    let mut cmsg_buf = [0u8; PKTINFO_CMSG_SPACE];
+    let mut cmsg_len = 0u32;
+
+    let mut remaining = recv_msg.Control.len as isize;
+    let mut cmsg = recv_msg.Control.buf as *mut WSACMSGHDR;
+    while remaining as usize >= mem::size_of::<WSACMSGHDR>() {
+        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+            (l, IP_PKTINFO) if l == IPPROTO_IP as i32 => {
+                let info = *(wsa_cmsg_data(cmsg) as *const IN_PKTINFO);
+                let out  = cmsg_buf.as_mut_ptr() as *mut WSACMSGHDR;
+                (*out).cmsg_level = IPPROTO_IP as i32;
+                (*out).cmsg_type  = IP_PKTINFO;
+                (*out).cmsg_len   = wsa_cmsg_space(mem::size_of::<IN_PKTINFO>());
+                ptr::write(wsa_cmsg_data(out) as *mut IN_PKTINFO, info);
+                cmsg_len = wsa_cmsg_space(mem::size_of::<IN_PKTINFO>()) as u32;
+            },
+            (l, IPV6_PKTINFO) if l == IPPROTO_IPV6 as i32 => {
+                let info = *(wsa_cmsg_data(cmsg) as *const IN6_PKTINFO);
+                let out  = cmsg_buf.as_mut_ptr() as *mut WSACMSGHDR;
+                (*out).cmsg_level = IPPROTO_IPV6 as i32;
+                (*out).cmsg_type  = IPV6_PKTINFO;
+                (*out).cmsg_len   = wsa_cmsg_space(mem::size_of::<IN6_PKTINFO>());
+                ptr::write(wsa_cmsg_data(out) as *mut IN6_PKTINFO, info);
+                cmsg_len = wsa_cmsg_space(mem::size_of::<IN6_PKTINFO>()) as u32;
+            },
+            _ => {},
+        }
+        let advance = (*cmsg).cmsg_len as isize;
+        cmsg       = (cmsg as *mut u8).offset(advance) as *mut WSACMSGHDR;
+        remaining -= advance;
+    }
+
+    (cmsg_buf, cmsg_len)
+}
+
+/// Receive a datagram, capturing its peer address and reply pktinfo as a
+/// [`UdpEndpoint`] rather than decoding them into `std::net` types.
+pub fn recv_endpoint(socket: RawSocket, buf: &mut [u8]) -> io::Result<(usize, UdpEndpoint)>
+{
+    let raw_socket = socket as SOCKET;
+    let recvmsg = unsafe { get_wsarecvmsg(raw_socket) }?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "WSARecvMsg is not available"))?;
+
+    let mut peer = OsSocketAddr::new();
+    let mut recv_cmsg = [0u8; PKTINFO_CMSG_SPACE];
+    let mut wbuf = WSABUF{len: buf.len() as u32, buf: buf.as_mut_ptr() as *mut i8};
+
+    let mut msg = WSAMSG{
+        name:           peer.as_mut_ptr() as *mut SOCKADDR,
+        namelen:        peer.capacity() as i32,
+        lpBuffers:      &mut wbuf,
+        dwBufferCount:  1,
+        Control:        WSABUF{len: recv_cmsg.len() as u32, buf: recv_cmsg.as_mut_ptr() as *mut i8},
+        dwFlags:        0,
+    };
+
+    let mut nb: DWORD = 0;
+    try_io!(unsafe { recvmsg(raw_socket, &mut msg, &mut nb, ptr::null_mut(), None) });
+    let (cmsg, cmsg_len) = unsafe { reply_pktinfo(&msg) };
+
+    Ok((nb as usize, UdpEndpoint{peer, cmsg, cmsg_len}))
+}
+
+/// Send a datagram to the peer captured by `endpoint`, using the source
+/// address and interface the original datagram was received on.
+pub fn send_endpoint(socket: RawSocket, endpoint: &UdpEndpoint, buf: &[u8]) -> io::Result<usize>
+{
+    let socket = socket as SOCKET;
+    let mut wbuf = WSABUF{len: buf.len() as u32, buf: buf.as_ptr() as *mut i8};
+    let mut cmsg = endpoint.cmsg;
+
+    let mut msg = WSAMSG{
+        name:           endpoint.peer.as_ptr() as *mut SOCKADDR,
+        namelen:        endpoint.peer.len() as i32,
+        lpBuffers:      &mut wbuf,
+        dwBufferCount:  1,
+        Control:        WSABUF{len: endpoint.cmsg_len, buf: cmsg.as_mut_ptr() as *mut i8},
+        dwFlags:        0,
+    };
+
+    let mut nb: DWORD = 0;
+    try_io!(unsafe { winsock2::WSASendMsg(socket, &mut msg, 0, &mut nb, ptr::null_mut(), None) });
+    Ok(nb as usize)
+}